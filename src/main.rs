@@ -1,63 +1,181 @@
 use std::{
-    env,
+    collections::HashMap,
     fs::File,
-    io::{BufReader, Read},
-    path::Path,
+    io::{BufReader, Read, Write},
+    path::{Path, PathBuf},
     process::exit,
+    time::SystemTime,
 };
 
-const VERSION: &str = std::env!("CARGO_PKG_VERSION");
+use chrono::{DateTime, Utc};
+use clap::Parser;
 
-const USAGE: &str = r#"
-usage: uniqopy <file>
+/// Default timestamp format when `--time-format` isn't given: a
+/// colon-free, sortable form, since colons are illegal in Windows
+/// filenames and awkward in URLs and shells everywhere.
+const DEFAULT_TIME_FORMAT_LOCAL: &str = "%Y%m%dT%H%M%S";
+const DEFAULT_TIME_FORMAT_UTC: &str = "%Y%m%dT%H%M%SZ";
 
-Create a copy of a file incorporating its MD5 hash and the current
-UTC timestamp into the new file's name. The file's extension will
-be retained.
+/// Create a copy of a file (or files) incorporating their hash and a
+/// timestamp into the new file's name. The file's extension will be
+/// retained.
+///
+/// Examples (with `--utc`; without it the timestamp is local time and has
+/// no trailing `Z`):
+///
+///     example -> example.20220202T222222Z.d41d8cd98f00b204e9800998ecf8427e
+///     example.txt -> example.20220202T222222Z.d41d8cd98f00b204e9800998ecf8427e.txt
+#[derive(Parser, Debug)]
+#[command(version, about, long_about)]
+struct Cli {
+    /// Files to uniq-copy.
+    files: Vec<PathBuf>,
+
+    /// Walk this directory tree and uniq-copy every regular file found in it.
+    #[arg(long)]
+    recursive: Option<PathBuf>,
+
+    /// Apply the source file's mtime/atime to the copy.
+    #[arg(long)]
+    preserve_times: bool,
+
+    /// Use the source file's modification time in the name instead of the
+    /// current time, so the name is deterministic for a file that hasn't
+    /// been modified since.
+    #[arg(long)]
+    mtime: bool,
 
-Examples:
-    example -> example.2022-02-02-22:22:22.d41d8cd98f00b204e9800998ecf8427e
-    example.txt -> example.2022-02-02-22:22:22.d41d8cd98f00b204e9800998ecf8427e.txt
-"#;
+    /// Hash algorithm to embed in the copy's name.
+    #[arg(long, value_enum, default_value_t = HashAlgo::Md5)]
+    hash: HashAlgo,
 
-/// Calculate the MD5 of a file using buffered reading. Used to get a (reasonably)
-/// unique signature for each input file.
+    /// Format the timestamp in UTC instead of local time.
+    #[arg(long)]
+    utc: bool,
+
+    /// A chrono strftime format string for the timestamp. Defaults to a
+    /// colon-free form (see DEFAULT_TIME_FORMAT_LOCAL/_UTC), since colons
+    /// aren't legal in Windows filenames. Pass "%F-%X" to recover the old
+    /// (colon-containing) default.
+    #[arg(long)]
+    time_format: Option<String>,
+
+    /// Append a provenance record for each copy to this JSON-lines
+    /// manifest file, so a mangled copy name can be mapped back to its
+    /// original without re-parsing the filename.
+    #[arg(long)]
+    manifest: Option<PathBuf>,
+
+    /// Skip copying when a file with the same hash and extension already
+    /// exists in the destination directory (or was already made earlier
+    /// in this run), reporting the existing path instead.
+    #[arg(long)]
+    dedup: bool,
+}
+
+/// The hash algorithms `uniqopy` can embed in a copy's name.
 ///
-/// Note that MD5 is [not cryptographically
-/// secure](https://en.wikipedia.org/wiki/MD5#Security), so you shouldn't rely
-/// on the uniqueness of this hash when accepting un-trusted input.
-fn md5_of_file(file_path: &Path) -> Result<String, std::io::Error> {
+/// Defaults to `Md5` for backward compatibility, but `Sha256` (or
+/// `Sha1`) should be preferred when the input may be untrusted, since
+/// MD5 is [not cryptographically
+/// secure](https://en.wikipedia.org/wiki/MD5#Security).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+enum HashAlgo {
+    Md5,
+    Sha1,
+    Sha256,
+}
+
+/// Calculate the hash of a file using buffered reading. Used to get a
+/// unique signature for each input file; which algorithm is "unique
+/// enough" depends on whether the input is trusted (see `HashAlgo`).
+fn hash_of_file(file_path: &Path, algo: HashAlgo) -> Result<String, std::io::Error> {
     let file = File::open(file_path)?;
     let mut reader = BufReader::new(file);
-    let mut context = md5::Context::new();
     let mut buffer = vec![0; 10 * 1024 * 1024]; // 10MB buffer
 
-    loop {
-        let bytes_read = reader.read(&mut buffer)?;
-        if bytes_read == 0 {
-            break;
+    match algo {
+        HashAlgo::Md5 => {
+            let mut context = md5::Context::new();
+            loop {
+                let bytes_read = reader.read(&mut buffer)?;
+                if bytes_read == 0 {
+                    break;
+                }
+                context.consume(&buffer[..bytes_read]);
+            }
+            Ok(format!("{:x}", context.compute()))
+        }
+        HashAlgo::Sha1 => {
+            use sha1::{Digest, Sha1};
+            let mut hasher = Sha1::new();
+            loop {
+                let bytes_read = reader.read(&mut buffer)?;
+                if bytes_read == 0 {
+                    break;
+                }
+                hasher.update(&buffer[..bytes_read]);
+            }
+            Ok(format!("{:x}", hasher.finalize()))
+        }
+        HashAlgo::Sha256 => {
+            use sha2::{Digest, Sha256};
+            let mut hasher = Sha256::new();
+            loop {
+                let bytes_read = reader.read(&mut buffer)?;
+                if bytes_read == 0 {
+                    break;
+                }
+                hasher.update(&buffer[..bytes_read]);
+            }
+            Ok(format!("{:x}", hasher.finalize()))
         }
-        context.consume(&buffer[..bytes_read]);
     }
+}
 
-    let digest = context.compute();
-    Ok(format!("{:x}", digest))
+/// Check that `format` is a valid chrono strftime format string, so a
+/// typo in `--time-format` is reported up front instead of silently
+/// producing a garbled (or un-creatable) filename.
+fn validate_time_format(format: &str) -> Result<(), String> {
+    use chrono::format::{Item, StrftimeItems};
+    for item in StrftimeItems::new(format) {
+        if let Item::Error = item {
+            return Err(format!("invalid --time-format: {}", format));
+        }
+    }
+    Ok(())
 }
 
-/// Generate a date-and-time-stamp using the system's local time.
-fn timestamp() -> String {
-    use chrono::{DateTime, Local};
-    let now: DateTime<Local> = Local::now();
-    format!("{}", now.format("%F-%X"))
+/// Generate a date-and-time-stamp using the given strftime `format`, in
+/// UTC if `utc` is set, otherwise in local time. Uses the system's
+/// current time, unless `source` is given, in which case that time is
+/// formatted instead (used by `--mtime` to derive the name from the
+/// file's modification time).
+fn timestamp(source: Option<SystemTime>, utc: bool, format: &str) -> String {
+    use chrono::{DateTime, Local, Utc};
+    if utc {
+        let when: DateTime<Utc> = match source {
+            Some(time) => time.into(),
+            None => Utc::now(),
+        };
+        format!("{}", when.format(format))
+    } else {
+        let when: DateTime<Local> = match source {
+            Some(time) => time.into(),
+            None => Local::now(),
+        };
+        format!("{}", when.format(format))
+    }
 }
 
 /// Construct a new filename, preserving file extension.
 ///
 /// For example:
 ///
-/// * `foo.jpg` becomes `foo.<timestamp>.<md5>.jpg`
-/// * `bar` becomes `bar.<timestamp>.<md5>`
-fn new_name(fname: &Path, ts: &str, md5: &str) -> Result<String, &'static str> {
+/// * `foo.jpg` becomes `foo.<timestamp>.<hash>.jpg`
+/// * `bar` becomes `bar.<timestamp>.<hash>`
+fn new_name(fname: &Path, ts: &str, hash: &str) -> Result<String, &'static str> {
     let fpath = std::path::Path::new(&fname);
     if !fpath.is_file() {
         return Err("uniqopy only works on files");
@@ -69,50 +187,235 @@ fn new_name(fname: &Path, ts: &str, md5: &str) -> Result<String, &'static str> {
     };
 
     let new_name = match fpath.extension() {
-        Some(ext) => format!("{}.{}.{}.{}", &fname, ts, md5, ext.to_string_lossy()),
-        None => format!("{}.{}.{}", fname, ts, md5),
+        Some(ext) => format!("{}.{}.{}.{}", &fname, ts, hash, ext.to_string_lossy()),
+        None => format!("{}.{}.{}", fname, ts, hash),
     };
     Ok(new_name)
 }
 
-fn main() {
-    let args: Vec<String> = env::args().collect();
-    let fpath = match &args[..] {
-        [_, fname] => Path::new(fname),
-        _ => {
-            eprint!("uniqopy version {}\n{}", VERSION, USAGE);
-            exit(1);
+/// Apply the source file's mtime and atime to the destination, so a
+/// `--preserve-times` copy keeps the original's temporal metadata even
+/// though its name and on-disk creation time are new.
+fn preserve_times(src: &Path, dst: &Path) -> Result<(), std::io::Error> {
+    let metadata = std::fs::metadata(src)?;
+    let atime = filetime::FileTime::from_last_access_time(&metadata);
+    let mtime = filetime::FileTime::from_last_modification_time(&metadata);
+    filetime::set_file_times(dst, atime, mtime)
+}
+
+/// A single row in the `--manifest` JSON-lines file, recording enough
+/// provenance to reconstruct the mapping from a mangled copy name back
+/// to its original file without re-parsing the filename.
+#[derive(serde::Serialize)]
+struct ManifestEntry {
+    original: String,
+    destination: String,
+    hash_algo: HashAlgo,
+    hash: String,
+    filename_timestamp: String,
+    time_imported: String,
+    time_modified: String,
+    size: u64,
+}
+
+/// Append `entry` as one line of JSON to `manifest_path`, creating the
+/// file if it doesn't already exist.
+fn append_manifest_entry(manifest_path: &Path, entry: &ManifestEntry) -> Result<(), String> {
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(manifest_path)
+        .map_err(|e| e.to_string())?;
+    let line = serde_json::to_string(entry).map_err(|e| e.to_string())?;
+    writeln!(file, "{}", line).map_err(|e| e.to_string())
+}
+
+/// Collect every regular file under `dir`, recursing into subdirectories.
+fn collect_files(dir: &Path) -> Result<Vec<PathBuf>, std::io::Error> {
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        if path.is_dir() {
+            files.extend(collect_files(&path)?);
+        } else if path.is_file() {
+            files.push(path);
         }
-    };
+    }
+    Ok(files)
+}
+
+/// The result of uniq-copying one file: either a fresh copy was made, or
+/// (with `--dedup`) an existing copy with the same content was found and
+/// the copy was skipped.
+enum UniqopyOutcome {
+    Copied(String),
+    Deduped(PathBuf),
+}
+
+/// Build the key `--dedup` uses to recognize "the same content, with the
+/// same hash algorithm and extension" across files in a single run.
+fn dedup_key(algo: HashAlgo, hash: &str, ext: Option<&str>) -> String {
+    format!("{:?}:{}:{}", algo, hash, ext.unwrap_or(""))
+}
+
+/// Look in `dir` for a uniqopy-style filename whose embedded hash (and
+/// extension) match `hash`/`ext`, without relying on the timestamp, which
+/// varies run to run.
+fn find_existing_copy(
+    dir: &Path,
+    hash: &str,
+    ext: Option<&str>,
+) -> Result<Option<PathBuf>, std::io::Error> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if !path.is_file() {
+            continue;
+        }
+        let name = match path.file_name().and_then(|n| n.to_str()) {
+            Some(name) => name,
+            None => continue,
+        };
+        let parts: Vec<&str> = name.split('.').collect();
+        let matches = match ext {
+            Some(ext) => {
+                parts.len() >= 2 && parts[parts.len() - 1] == ext && parts[parts.len() - 2] == hash
+            }
+            None => parts.last() == Some(&hash),
+        };
+        if matches {
+            return Ok(Some(path));
+        }
+    }
+    Ok(None)
+}
+
+/// Uniq-copy a single file according to `cli`'s options. `seen` caches
+/// digests already copied earlier in this run, so a batch/recursive run
+/// over many files also collapses duplicates within a single invocation.
+fn uniqopy_file(
+    fpath: &Path,
+    cli: &Cli,
+    time_format: &str,
+    seen: &mut HashMap<String, PathBuf>,
+) -> Result<UniqopyOutcome, String> {
+    let hash = hash_of_file(fpath, cli.hash)
+        .map_err(|e| format!("Error reading {}: {}", fpath.to_string_lossy(), e))?;
 
-    // Get md5 of file contents
-    let md5 = match md5_of_file(fpath) {
-        Ok(hash) => hash,
-        Err(e) => {
-            eprintln!("Error reading {}: {}", &fpath.to_string_lossy(), e);
-            exit(2);
+    let metadata = std::fs::metadata(fpath)
+        .map_err(|e| format!("Error reading {}: {}", fpath.to_string_lossy(), e))?;
+
+    let ext = fpath.extension().and_then(|e| e.to_str());
+
+    if cli.dedup {
+        let key = dedup_key(cli.hash, &hash, ext);
+        if let Some(existing) = seen.get(&key) {
+            return Ok(UniqopyOutcome::Deduped(existing.clone()));
         }
+        if let Some(existing) = find_existing_copy(Path::new("."), &hash, ext)
+            .map_err(|e| format!("Error scanning destination directory: {}", e))?
+        {
+            seen.insert(key, existing.clone());
+            return Ok(UniqopyOutcome::Deduped(existing));
+        }
+    }
+
+    let ts = if cli.mtime {
+        let mtime = metadata
+            .modified()
+            .map_err(|e| format!("Error reading {}: {}", fpath.to_string_lossy(), e))?;
+        timestamp(Some(mtime), cli.utc, time_format)
+    } else {
+        timestamp(None, cli.utc, time_format)
     };
 
-    // Get timestamp
-    let ts = timestamp();
+    let destination = new_name(fpath, &ts, &hash)?;
+
+    std::fs::copy(fpath, &destination).map_err(|e| e.to_string())?;
+
+    if cli.preserve_times {
+        preserve_times(fpath, Path::new(&destination)).map_err(|e| e.to_string())?;
+    }
+
+    if let Some(manifest_path) = &cli.manifest {
+        let time_modified: DateTime<Utc> = metadata.modified().map_err(|e| e.to_string())?.into();
+        let time_imported: DateTime<Utc> = Utc::now();
+        let entry = ManifestEntry {
+            original: fpath.to_string_lossy().into_owned(),
+            destination: destination.clone(),
+            hash_algo: cli.hash,
+            hash: hash.clone(),
+            filename_timestamp: ts,
+            time_imported: time_imported.to_rfc3339(),
+            time_modified: time_modified.to_rfc3339(),
+            size: metadata.len(),
+        };
+        append_manifest_entry(manifest_path, &entry)?;
+    }
+
+    if cli.dedup {
+        seen.insert(dedup_key(cli.hash, &hash, ext), PathBuf::from(&destination));
+    }
+
+    Ok(UniqopyOutcome::Copied(destination))
+}
 
-    // Copy file to new name
-    let destination = match new_name(fpath, &ts, &md5) {
-        Ok(nm) => nm,
-        Err(e) => {
+fn main() {
+    let cli = Cli::parse();
+
+    if let Some(format) = &cli.time_format {
+        if let Err(e) = validate_time_format(format) {
             eprintln!("{}", e);
-            exit(3)
+            exit(1);
         }
-    };
-    println!("Copying {} to {}", fpath.to_string_lossy(), destination);
-    match std::fs::copy(fpath, &destination) {
-        Ok(bytes) => {
-            println!("Copyied {} bytes", bytes);
+    }
+    let time_format = cli.time_format.as_deref().unwrap_or(if cli.utc {
+        DEFAULT_TIME_FORMAT_UTC
+    } else {
+        DEFAULT_TIME_FORMAT_LOCAL
+    });
+
+    let mut targets = cli.files.clone();
+    if let Some(dir) = &cli.recursive {
+        match collect_files(dir) {
+            Ok(files) => targets.extend(files),
+            Err(e) => {
+                eprintln!("Error walking {}: {}", dir.to_string_lossy(), e);
+                exit(1);
+            }
         }
-        Err(e) => {
-            eprintln!("{}", e);
-            exit(4);
+    }
+
+    if targets.is_empty() {
+        eprintln!("uniqopy: no files given (pass a file, or --recursive <dir>)");
+        exit(1);
+    }
+
+    let mut seen = HashMap::new();
+    let mut failures = 0;
+    for fpath in &targets {
+        match uniqopy_file(fpath, &cli, time_format, &mut seen) {
+            Ok(UniqopyOutcome::Copied(destination)) => {
+                println!("{} -> {}", fpath.to_string_lossy(), destination)
+            }
+            Ok(UniqopyOutcome::Deduped(existing)) => println!(
+                "{} already copied as {}, skipping",
+                fpath.to_string_lossy(),
+                existing.to_string_lossy()
+            ),
+            Err(e) => {
+                eprintln!("{}: {}", fpath.to_string_lossy(), e);
+                failures += 1;
+            }
         }
-    };
+    }
+
+    println!(
+        "{} succeeded, {} failed",
+        targets.len() - failures,
+        failures
+    );
+    if failures > 0 {
+        exit(1);
+    }
 }